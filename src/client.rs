@@ -1,19 +1,52 @@
-use super::protocol::{Listing, PatchRequest};
+use super::crypto::{self, CLIENT_PUBKEY_HEADER};
+use super::protocol::{Listing, PatchRequest, VersionInfo, PROTOCOL_VERSION};
 use fast_rsync::{apply, Signature, SignatureOptions};
 use pretty_bytes::converter::convert as bytes_pretty;
+use rand::rngs::OsRng;
+use serde::Serialize;
 use sha2::{Digest, Sha256};
 use std::fmt::Display;
 use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use x25519_dalek::{PublicKey, StaticSecret};
 
 type Error = Box<dyn std::error::Error + Send + Sync>;
 
-#[derive(Debug)]
 pub struct Client {
     server_base: String,
     workdir: PathBuf,
+    format: OutputFormat,
+    mirror: bool,
+    jobs: usize,
+    pinned_key: Option<String>,
+    http: reqwest::Client,
+    secret: StaticSecret,
+    public: PublicKey,
 }
 
-#[derive(Debug)]
+/// How per-file progress is rendered on stdout.
+#[derive(Debug, Clone, Copy)]
+pub enum OutputFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(OutputFormat::Text),
+            "json" => Ok(OutputFormat::Json),
+            other => Err(format!("unknown output format: {}", other)),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
 struct FilePatchStats {
     file: PathBuf,
     original_size: usize,
@@ -36,59 +69,250 @@ impl Display for FilePatchStats {
 }
 
 impl Client {
-    pub fn new(server: String, directory: PathBuf) -> Self {
+    pub fn new(
+        server: String,
+        directory: PathBuf,
+        format: OutputFormat,
+        mirror: bool,
+        jobs: usize,
+        pinned_key: Option<String>,
+    ) -> Self {
+        // Fresh ephemeral keypair for this session
+        let secret = StaticSecret::new(OsRng);
+        let public = PublicKey::from(&secret);
         Self {
             server_base: server,
             workdir: directory,
+            format,
+            mirror,
+            jobs,
+            pinned_key,
+            // A single client pools connections across all concurrent requests
+            http: reqwest::Client::new(),
+            secret,
+            public,
         }
     }
 
     #[tokio::main]
-    pub async fn run(&self) {
+    pub async fn run(self) {
         // Log mode info
         log::info!("Running in client mode...");
 
         // Run update
-        if let Err(e) = self.update().await {
+        let this = Arc::new(self);
+        if let Err(e) = this.update().await {
             log::error!("{}", e);
         }
     }
 
-    async fn update(&self) -> Result<(), Error> {
+    async fn update(self: &Arc<Self>) -> Result<(), Error> {
+        // Negotiate protocol version and capabilities before anything else
+        let info = self.negotiate().await?;
+        log::info!("Server capabilities: {}", info.capabilities.join(", "));
+        let has = |cap: &str| info.capabilities.iter().any(|c| c == cap);
+
+        // Only run the encrypted channel when the server advertises it; otherwise
+        // fall back to plaintext rather than sealing messages it cannot open
+        let key = if has("encryption") {
+            log::info!("Establishing secure channel");
+            Some(self.establish_key().await?)
+        } else {
+            log::warn!("Server does not support encryption, proceeding in plaintext");
+            None
+        };
+
         // Fetch list of files
         log::info!("Fetching listing");
-        let listing = self.fetch_listing().await?;
-
-        // Update filelist
-        for file in listing.files {
-            log::info!("Updating file {:?}", file.path);
-            let result = self.update_file(&file.path, &file.hash).await?;
-            match result {
-                Some(stat) => log::info!("{}", &stat),
-                None => (),
+        let listing = self.fetch_listing(key).await?;
+
+        // Mirror mode needs server-side support to be safe
+        let mirror = self.mirror && has("delete-sync");
+        if self.mirror && !mirror {
+            log::warn!("Server does not support delete-sync, ignoring --delete");
+        }
+
+        // Patch files concurrently, bounding in-flight jobs with a semaphore
+        // (at least one permit, so `--jobs 0` can't deadlock every task)
+        let sem = Arc::new(Semaphore::new(self.jobs.max(1)));
+        let mut set = JoinSet::new();
+        for file in &listing.files {
+            let this = self.clone();
+            let sem = sem.clone();
+            let path = file.path.clone();
+            let hash = file.hash.clone();
+            set.spawn(async move {
+                let _permit = sem.acquire_owned().await.unwrap();
+                log::info!("Updating file {:?}", path);
+                this.update_file(key, &path, &hash).await
+            });
+        }
+
+        // Aggregate stats as tasks complete, surfacing the first error and
+        // cancelling any jobs still in flight
+        let mut result = Ok(());
+        while let Some(joined) = set.join_next().await {
+            match joined {
+                Ok(Ok(Some(stat))) => self.report(&stat),
+                Ok(Ok(None)) => (),
+                Ok(Err(e)) => {
+                    result = Err(e);
+                    break;
+                }
+                Err(e) => {
+                    result = Err(e.into());
+                    break;
+                }
             }
         }
+        set.abort_all();
+        result?;
+
+        // In mirror mode, prune any local file no longer present on the server
+        if mirror {
+            self.prune(&listing)?;
+        }
         Ok(())
     }
 
-    async fn fetch_listing(&self) -> Result<Listing, Error> {
+    fn report(&self, stat: &FilePatchStats) {
+        // Human log line by default, structured event stream with `--format json`
+        match self.format {
+            OutputFormat::Text => log::info!("{}", stat),
+            OutputFormat::Json => println!("{}", serde_json::to_string(stat).unwrap()),
+        }
+    }
+
+    fn prune(&self, listing: &Listing) -> Result<(), Error> {
+        use std::collections::HashSet;
+
+        // Set of paths the server still advertises
+        let remote: HashSet<PathBuf> = listing.files.iter().map(|e| e.path.clone()).collect();
+
+        // Remove every local file not present remotely
+        for rel in Self::list_entries(&self.workdir) {
+            if !remote.contains(&rel) {
+                let path = self.workdir.join(&rel);
+                std::fs::remove_file(&path)?;
+                self.report_removed(&rel);
+            }
+        }
+
+        // Drop directories left empty by the removals
+        Self::prune_empty_dirs(&self.workdir)?;
+        Ok(())
+    }
+
+    fn report_removed(&self, file: &PathBuf) {
+        match self.format {
+            OutputFormat::Text => log::info!("Removed stale file {:?}", file),
+            OutputFormat::Json => {
+                println!("{}", serde_json::json!({ "file": file, "removed": true }))
+            }
+        }
+    }
+
+    fn list_entries(dir: &PathBuf) -> Vec<PathBuf> {
+        walkdir::WalkDir::new(&dir)
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .filter_map(|e| {
+                let p = e.into_path();
+                p.metadata()
+                    .unwrap()
+                    .is_file()
+                    .then(|| p.strip_prefix(dir).unwrap().to_path_buf())
+            })
+            .collect()
+    }
+
+    fn prune_empty_dirs(dir: &PathBuf) -> Result<(), Error> {
+        for entry in walkdir::WalkDir::new(dir).contents_first(true) {
+            let entry = entry?;
+            let path = entry.path();
+            if path == dir.as_path() {
+                continue;
+            }
+            if entry.file_type().is_dir() && std::fs::read_dir(path)?.next().is_none() {
+                std::fs::remove_dir(path)?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn negotiate(&self) -> Result<VersionInfo, Error> {
+        // Fetch the server's advertised protocol version and capabilities
+        let url = format!("{}{}", self.server_base, "/version");
+        let resp = self.http.get(&url).send().await?;
+        let info = resp.json::<VersionInfo>().await?;
+
+        // A differing major version means incompatible wire formats
+        if info.version.major != PROTOCOL_VERSION.major {
+            return Err(format!(
+                "incompatible server protocol version {} (client speaks {})",
+                info.version, PROTOCOL_VERSION
+            )
+            .into());
+        }
+
+        Ok(info)
+    }
+
+    async fn establish_key(&self) -> Result<[u8; 32], Error> {
+        // Fetch the server's static public key
+        let url = format!("{}{}", self.server_base, "/pubkey");
+        let resp = self.http.get(&url).send().await?;
+        let advertised = resp.text().await?.trim().to_string();
+
+        // Pin the key when the caller supplied one out of band; without pinning
+        // an active MITM could substitute its own key (passive safety only)
+        match &self.pinned_key {
+            Some(expected) if &advertised != expected => {
+                return Err("server public key does not match the pinned key".into());
+            }
+            Some(_) => (),
+            None => log::warn!(
+                "No --server-key pinned: the channel is only safe against passive eavesdroppers"
+            ),
+        }
+
+        let pubb = base64::decode(&advertised)?;
+        if pubb.len() != 32 {
+            return Err("invalid server public key length".into());
+        }
+        let mut bytes = [0u8; 32];
+        bytes.copy_from_slice(&pubb);
+
+        // Diffie-Hellman against our ephemeral secret and derive the AES key
+        let shared = self.secret.diffie_hellman(&PublicKey::from(bytes));
+        Ok(crypto::derive_key(shared.as_bytes()))
+    }
+
+    async fn fetch_listing(&self, key: Option<[u8; 32]>) -> Result<Listing, Error> {
         // Construct request url
         let url = format!("{}{}", self.server_base, "/list");
 
-        // Create the client
-        let client = reqwest::Client::new();
-
-        // Make the request
-        let req = client.get(&url);
+        // Advertise our ephemeral public key only on the encrypted path
+        let mut req = self.http.get(&url);
+        if key.is_some() {
+            req = req.header(CLIENT_PUBKEY_HEADER, base64::encode(self.public.as_bytes()));
+        }
         let resp = req.send().await?;
-        let body = resp.json::<Listing>().await?;
+        let bytes = resp.bytes().await?;
+
+        // Open the sealed body, or take it verbatim in plaintext mode
+        let body = match key {
+            Some(key) => crypto::open(&key, &bytes)?,
+            None => bytes.to_vec(),
+        };
 
         // Return result
-        Ok(body)
+        Ok(serde_json::from_slice::<Listing>(&body)?)
     }
 
     async fn update_file(
         &self,
+        key: Option<[u8; 32]>,
         file: &PathBuf,
         hash: &String,
     ) -> Result<Option<FilePatchStats>, Error> {
@@ -110,35 +334,106 @@ impl Client {
         }
         log::info!("File {:?} remote differ, performing update", file);
 
-        // Calculate file signature
-        log::info!("Calculating signature for file {:?}", file);
-        let sigb = Self::make_signature(&data[..]);
-        let signature = base64::encode(&sigb);
-
-        // Fetch patch for file
+        // Fetch and apply a delta against the local data
         log::info!("Fetching patch for file {:?}", file);
-        let patch = self.fetch_patch(file, &signature).await?;
+        let (mut output, mut patch_size) = self.fetch_and_apply(key, file, &data, &data).await?;
 
-        // Apply patch
-        log::info!("Applying patch for file {:?}", file);
-        let mut output = Vec::new();
-        apply(&data[..], &patch, &mut output)?;
+        // Confirm the patched result matches the server's advertised hash; a corrupted
+        // patch or a signature collision would otherwise produce a bad file silently
+        if !Self::verify(&output, hash) {
+            log::warn!(
+                "File {:?} failed integrity check, retrying with a full transfer",
+                file
+            );
+            // An empty signature forces the server to emit a literal full-file copy
+            let (o, ps) = self.fetch_and_apply(key, file, &[], &[]).await?;
+            output = o;
+            patch_size = ps;
+            if !Self::verify(&output, hash) {
+                // The file may have changed on the server between `/list` and
+                // `/patch` (chunk0-7 live refresh); re-fetch its current hash
+                // before treating this as a genuine corruption.
+                match self.fetch_file_hash(key, file).await? {
+                    Some(current) if Self::verify(&output, &current) => {
+                        log::info!(
+                            "File {:?} changed on server during update, accepted current revision",
+                            file
+                        );
+                    }
+                    _ => {
+                        return Err(format!("integrity verification failed for {:?}", file).into())
+                    }
+                }
+            }
+        }
 
-        // Write file
+        // Write to a temporary file and atomically rename so a crash mid-write
+        // never leaves a truncated file in place
         std::fs::create_dir_all(&path.parent().unwrap())?;
-        std::fs::write(&path, &output)?;
+        let tmp = Self::temp_path(&path);
+        std::fs::write(&tmp, &output)?;
+        std::fs::rename(&tmp, &path)?;
 
         // Gather update stats
         let stats = FilePatchStats {
             file: file.clone(),
             original_size: data.len(),
-            patch_size: patch.len(),
+            patch_size,
             new_size: output.len(),
         };
         Ok(Some(stats))
     }
 
-    async fn fetch_patch(&self, file: &PathBuf, sig: &String) -> Result<Vec<u8>, Error> {
+    async fn fetch_and_apply(
+        &self,
+        key: Option<[u8; 32]>,
+        file: &PathBuf,
+        base: &[u8],
+        sig_data: &[u8],
+    ) -> Result<(Vec<u8>, usize), Error> {
+        // Calculate file signature
+        let sigb = Self::make_signature(sig_data);
+        let signature = base64::encode(&sigb);
+
+        // Fetch patch and apply it against the base
+        let patch = self.fetch_patch(key, file, &signature).await?;
+        let mut output = Vec::new();
+        apply(base, &patch, &mut output)?;
+        Ok((output, patch.len()))
+    }
+
+    async fn fetch_file_hash(
+        &self,
+        key: Option<[u8; 32]>,
+        file: &PathBuf,
+    ) -> Result<Option<String>, Error> {
+        // Look up a single file's currently advertised hash
+        let listing = self.fetch_listing(key).await?;
+        Ok(listing
+            .files
+            .into_iter()
+            .find(|e| &e.path == file)
+            .map(|e| e.hash))
+    }
+
+    fn verify(data: &[u8], expected: &String) -> bool {
+        let mut hasher = Sha256::new();
+        hasher.update(data);
+        base64::encode(&hasher.finalize()) == *expected
+    }
+
+    fn temp_path(path: &PathBuf) -> PathBuf {
+        let mut name = path.file_name().unwrap().to_owned();
+        name.push(".part");
+        path.with_file_name(name)
+    }
+
+    async fn fetch_patch(
+        &self,
+        key: Option<[u8; 32]>,
+        file: &PathBuf,
+        sig: &String,
+    ) -> Result<Vec<u8>, Error> {
         // Construct request url and body
         let url = format!("{}{}", self.server_base, "/patch");
         let req_body = PatchRequest {
@@ -147,16 +442,23 @@ impl Client {
         };
         let req_json = serde_json::to_vec_pretty(&req_body).unwrap();
 
-        // Create the client
-        let client = reqwest::Client::new();
-
-        // Make the request
-        let req = client.post(&url).body(req_json);
-        let resp = req.send().await?;
+        // Seal the request body on the encrypted path, advertising our public key
+        let mut req = self.http.post(&url);
+        let req_json = match key {
+            Some(key) => {
+                req = req.header(CLIENT_PUBKEY_HEADER, base64::encode(self.public.as_bytes()));
+                crypto::seal(&key, &req_json)?
+            }
+            None => req_json,
+        };
+        let resp = req.body(req_json).send().await?;
         let bytes = resp.bytes().await?;
 
-        // Return result
-        Ok(bytes.to_vec())
+        // Open the sealed patch, or take it verbatim in plaintext mode
+        match key {
+            Some(key) => crypto::open(&key, &bytes),
+            None => Ok(bytes.to_vec()),
+        }
     }
 
     fn make_signature(data: &[u8]) -> Vec<u8> {
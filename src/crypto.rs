@@ -0,0 +1,49 @@
+use aes_gcm::aead::{Aead, NewAead};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use hkdf::Hkdf;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use sha2::Sha256;
+
+type Error = Box<dyn std::error::Error + Send + Sync>;
+
+/// Header carrying the client's ephemeral X25519 public key (base64) on every encrypted request.
+pub const CLIENT_PUBKEY_HEADER: &str = "x-client-pubkey";
+
+/// Length of the AES-256-GCM nonce prepended to every sealed message.
+const NONCE_LEN: usize = 12;
+
+/// Derive the AES-256 session key from a raw X25519 shared secret via HKDF-SHA256.
+pub fn derive_key(shared_secret: &[u8; 32]) -> [u8; 32] {
+    let hk = Hkdf::<Sha256>::new(None, shared_secret);
+    let mut key = [0u8; 32];
+    hk.expand(b"blocky patch channel", &mut key).unwrap();
+    key
+}
+
+/// Seal a message with AES-256-GCM, prepending a fresh random 12-byte nonce.
+pub fn seal(key: &[u8; 32], plaintext: &[u8]) -> Result<Vec<u8>, Error> {
+    let cipher = Aes256Gcm::new(Key::from_slice(key));
+    let mut nonce = [0u8; NONCE_LEN];
+    OsRng.fill_bytes(&mut nonce);
+    let ciphertext = cipher
+        .encrypt(Nonce::from_slice(&nonce), plaintext)
+        .map_err(|e| format!("encryption failed: {}", e))?;
+    let mut out = Vec::with_capacity(NONCE_LEN + ciphertext.len());
+    out.extend_from_slice(&nonce);
+    out.extend_from_slice(&ciphertext);
+    Ok(out)
+}
+
+/// Open an AES-256-GCM message, rejecting any that fails the authentication tag.
+pub fn open(key: &[u8; 32], message: &[u8]) -> Result<Vec<u8>, Error> {
+    if message.len() < NONCE_LEN {
+        return Err("message too short to contain a nonce".into());
+    }
+    let (nonce, ciphertext) = message.split_at(NONCE_LEN);
+    let cipher = Aes256Gcm::new(Key::from_slice(key));
+    let plaintext = cipher
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| "authentication failed")?;
+    Ok(plaintext)
+}
@@ -1,11 +1,12 @@
 extern crate log;
 
 mod client;
+mod crypto;
 mod protocol;
 mod server;
 
 use clap::Clap;
-use client::Client;
+use client::{Client, OutputFormat};
 use server::Server;
 use std::net::SocketAddr;
 use std::path::PathBuf;
@@ -34,6 +35,18 @@ struct ClientOpts {
     /// Sets the target directory.
     #[clap()]
     directory: PathBuf,
+    /// Sets the progress output format (text or json)
+    #[clap(long, default_value = "text")]
+    format: OutputFormat,
+    /// Mirrors the server, removing local files absent remotely
+    #[clap(long)]
+    delete: bool,
+    /// Sets the number of files patched concurrently
+    #[clap(long, default_value = "4")]
+    jobs: usize,
+    /// Pins the expected server public key (base64) for out-of-band trust
+    #[clap(long)]
+    server_key: Option<String>,
 }
 
 #[derive(Clap)]
@@ -51,7 +64,17 @@ fn main() {
     // Parse command line arguments and act accordingly
     let opts: Opts = Opts::parse();
     match opts.mode {
-        Mode::Client(opts) => Client::new(opts.server, opts.directory).run(),
+        Mode::Client(opts) => {
+            Client::new(
+                opts.server,
+                opts.directory,
+                opts.format,
+                opts.delete,
+                opts.jobs,
+                opts.server_key,
+            )
+            .run()
+        }
         Mode::Server(opts) => Server::new(opts.bind).run(),
     }
 }
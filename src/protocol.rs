@@ -1,6 +1,30 @@
 use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
 
+/// Wire protocol version. The major component must match between client and server;
+/// a differing minor is forward-compatible.
+pub const PROTOCOL_VERSION: Version = Version { major: 1, minor: 0 };
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Version {
+    pub major: u32,
+    pub minor: u32,
+}
+
+impl std::fmt::Display for Version {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(formatter, "{}.{}", self.major, self.minor)
+    }
+}
+
+/// Version and capability set exchanged during the handshake so clients can
+/// gracefully downgrade behavior when talking to older servers.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct VersionInfo {
+    pub version: Version,
+    pub capabilities: Vec<String>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Listing {
     pub files: Vec<ListingEntry>,
@@ -17,3 +41,13 @@ pub struct PatchRequest {
     pub file: PathBuf,
     pub sig: String,
 }
+
+/// Progress events broadcast by the server to `/events` subscribers as a
+/// machine-readable stream.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum ProgressEvent {
+    ListingLoaded { files: usize },
+    PatchStart { file: PathBuf, size: u64 },
+    PatchComplete { file: PathBuf, patch_size: usize },
+}
@@ -1,26 +1,46 @@
 use std::net::SocketAddr;
 use std::path::PathBuf;
-use std::sync::Arc;
+use std::os::unix::fs::PermissionsExt;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
 
 use base64;
 use serde_json;
 use walkdir;
 
-use super::protocol::{Listing, ListingEntry, PatchRequest};
+use super::crypto::{self, CLIENT_PUBKEY_HEADER};
+use super::protocol::{
+    Listing, ListingEntry, PatchRequest, ProgressEvent, VersionInfo, PROTOCOL_VERSION,
+};
 use fast_rsync::{diff, Signature};
 use hyper::service::{make_service_fn, service_fn};
 use hyper::{Body, Method, Request, Response, StatusCode};
+use notify_debouncer_mini::notify::{RecommendedWatcher, RecursiveMode};
+use notify_debouncer_mini::{new_debouncer, DebounceEventResult, Debouncer};
 use pretty_bytes::converter::convert as bytes_pretty;
+use rand::rngs::OsRng;
 use sha2::{Digest, Sha256};
+use tokio::sync::broadcast;
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::StreamExt;
+use x25519_dalek::{PublicKey, StaticSecret};
 
 type Error = Box<dyn std::error::Error + Send + Sync>;
 
+/// File the server's persistent X25519 secret key is stored in.
+const KEYFILE: &str = "server.key";
+
+/// Capabilities this server implements, advertised during the handshake.
+const CAPABILITIES: &[&str] = &["encryption", "delete-sync", "events"];
+
 pub struct Server {
     bind_addr: SocketAddr,
 }
 
 struct ServerContext {
-    listing: Listing,
+    listing: RwLock<Listing>,
+    secret: StaticSecret,
+    events: broadcast::Sender<ProgressEvent>,
 }
 
 impl Server {
@@ -46,8 +66,41 @@ impl Server {
             }
         }
 
+        // Load (or generate on first run) the server's static keypair
+        let secret;
+        match Self::load_keypair() {
+            Ok(s) => secret = s,
+            Err(e) => {
+                log::error!("Could not load server keypair: {}", e);
+                return;
+            }
+        }
+
+        // Pub-sub channel for pushing progress to `/events` subscribers
+        let (events, _) = broadcast::channel(64);
+
         // Server context is shared between services
-        let ctx = Arc::new(ServerContext { listing });
+        let ctx = Arc::new(ServerContext {
+            listing: RwLock::new(listing),
+            secret,
+            events,
+        });
+
+        // Announce the freshly loaded listing; a broadcast only reaches receivers
+        // already subscribed, so `/events` clients that connect later do not see it
+        let _ = ctx.events.send(ProgressEvent::ListingLoaded {
+            files: ctx.listing.read().unwrap().files.len(),
+        });
+
+        // Keep the listing authoritative by watching the working directory for changes
+        let _watcher;
+        match Self::spawn_watcher(ctx.clone()) {
+            Ok(w) => _watcher = w,
+            Err(e) => {
+                log::error!("Could not start filesystem watcher: {}", e);
+                return;
+            }
+        }
 
         // For every connection, we must make a `Service` to handle all
         // incoming HTTP requests on said connection.
@@ -92,6 +145,69 @@ impl Server {
         Ok(Listing { files })
     }
 
+    fn spawn_watcher(ctx: Arc<ServerContext>) -> Result<Debouncer<RecommendedWatcher>, Error> {
+        // Watch the same directory the listing was built from
+        let dir = std::env::current_dir()?;
+        let watch_dir = dir.clone();
+        let mut debouncer = new_debouncer(
+            Duration::from_secs(2),
+            move |res: DebounceEventResult| match res {
+                Ok(events) => {
+                    for event in events {
+                        Self::refresh_entry(&ctx, &dir, &event.path);
+                    }
+                }
+                Err(e) => log::error!("Watch error: {:?}", e),
+            },
+        )?;
+        debouncer
+            .watcher()
+            .watch(&watch_dir, RecursiveMode::Recursive)?;
+        log::info!("Watching {:?} for changes", watch_dir);
+        Ok(debouncer)
+    }
+
+    fn refresh_entry(ctx: &ServerContext, dir: &PathBuf, path: &PathBuf) {
+        // Translate the absolute event path back to a listing-relative path
+        let rel = match path.strip_prefix(dir) {
+            Ok(r) => r.to_path_buf(),
+            Err(_) => return,
+        };
+
+        // Never expose the server's own secret keyfile in the listing
+        if rel == PathBuf::from(KEYFILE) {
+            return;
+        }
+
+        // Recompute the affected entry outside the lock, so the fs read and
+        // SHA-256 don't stall `/list` readers for the duration of the change
+        let entry = if path.is_file() {
+            match Self::list_entry_for_file(&rel) {
+                Ok(entry) => Some(entry),
+                Err(e) => {
+                    log::error!("Could not refresh entry {:?}: {}", rel, e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        // Swap the finished entry in under a short-lived write lock. Drop by
+        // prefix, not exact path, so a single directory-removal event also
+        // prunes every child entry beneath it.
+        let files = {
+            let mut listing = ctx.listing.write().unwrap();
+            listing.files.retain(|e| !e.path.starts_with(&rel));
+            if let Some(entry) = entry {
+                listing.files.push(entry);
+            }
+            listing.files.len()
+        };
+        log::info!("Refreshed listing entry for {:?}", rel);
+        let _ = ctx.events.send(ProgressEvent::ListingLoaded { files });
+    }
+
     async fn handler(ctx: &ServerContext, req: Request<Body>) -> Result<Response<Body>, Error> {
         // Pass request to router
         let response = Self::router(ctx, req).await;
@@ -112,12 +228,63 @@ impl Server {
     async fn router(ctx: &ServerContext, req: Request<Body>) -> Result<Response<Body>, Error> {
         match (req.method(), req.uri().path()) {
             (&Method::GET, "/") => Self::route_home(ctx, req).await,
+            (&Method::GET, "/version") => Self::route_version(ctx, req).await,
+            (&Method::GET, "/events") => Self::route_events(ctx, req).await,
+            (&Method::GET, "/pubkey") => Self::route_pubkey(ctx, req).await,
             (&Method::GET, "/list") => Self::route_list(ctx, req).await,
             (&Method::POST, "/patch") => Self::route_patch(ctx, req).await,
             _ => Self::route_notfound(ctx, req).await,
         }
     }
 
+    async fn route_version(
+        _ctx: &ServerContext,
+        _req: Request<Body>,
+    ) -> Result<Response<Body>, Error> {
+        // Advertise protocol version and supported capabilities
+        let info = VersionInfo {
+            version: PROTOCOL_VERSION,
+            capabilities: CAPABILITIES.iter().map(|c| c.to_string()).collect(),
+        };
+        let response = Response::builder()
+            .status(StatusCode::OK)
+            .body(Body::from(serde_json::to_vec_pretty(&info).unwrap()))
+            .unwrap();
+        Ok(response)
+    }
+
+    async fn route_events(ctx: &ServerContext, req: Request<Body>) -> Result<Response<Body>, Error> {
+        // Derive the per-session key so event metadata isn't leaked in the clear
+        let key = Self::shared_key(ctx, &req)?;
+
+        // Stream broadcast progress events as sealed `text/event-stream` frames
+        let rx = ctx.events.subscribe();
+        let stream = BroadcastStream::new(rx).map(move |ev| -> Result<String, Error> {
+            let ev = ev?;
+            let sealed = crypto::seal(&key, serde_json::to_string(&ev)?.as_bytes())?;
+            Ok(format!("data: {}\n\n", base64::encode(&sealed)))
+        });
+        let response = Response::builder()
+            .status(StatusCode::OK)
+            .header("content-type", "text/event-stream")
+            .body(Body::wrap_stream(stream))
+            .unwrap();
+        Ok(response)
+    }
+
+    async fn route_pubkey(
+        ctx: &ServerContext,
+        _req: Request<Body>,
+    ) -> Result<Response<Body>, Error> {
+        // Advertise the server's static X25519 public key
+        let public = PublicKey::from(&ctx.secret);
+        let response = Response::builder()
+            .status(StatusCode::OK)
+            .body(Body::from(base64::encode(public.as_bytes())))
+            .unwrap();
+        Ok(response)
+    }
+
     async fn route_home(
         _ctx: &ServerContext,
         _req: Request<Body>,
@@ -130,11 +297,15 @@ impl Server {
         Ok(response)
     }
 
-    async fn route_list(ctx: &ServerContext, _req: Request<Body>) -> Result<Response<Body>, Error> {
-        // Serialize body data
-        let body = move || -> Result<_, Error> {
-            Ok(serde_json::to_vec_pretty(&ctx.listing).unwrap()) // TODO
-        }()?;
+    async fn route_list(ctx: &ServerContext, req: Request<Body>) -> Result<Response<Body>, Error> {
+        // Derive the per-session key from the client's ephemeral public key
+        let key = Self::shared_key(ctx, &req)?;
+
+        // Serialize the current listing under a read lock
+        let body = serde_json::to_vec_pretty(&*ctx.listing.read().unwrap())?;
+
+        // Seal the listing before it leaves the server
+        let body = crypto::seal(&key, &body)?;
 
         // Return response
         let response = Response::builder()
@@ -144,12 +315,13 @@ impl Server {
         Ok(response)
     }
 
-    async fn route_patch(
-        _ctx: &ServerContext,
-        req: Request<Body>,
-    ) -> Result<Response<Body>, Error> {
-        // Deserialize request body
+    async fn route_patch(ctx: &ServerContext, req: Request<Body>) -> Result<Response<Body>, Error> {
+        // Derive the per-session key from the client's ephemeral public key
+        let key = Self::shared_key(ctx, &req)?;
+
+        // Deserialize (and authenticate) request body
         let req_body = hyper::body::to_bytes(req.into_body()).await?;
+        let req_body = crypto::open(&key, &req_body)?;
         let patch_req = serde_json::from_slice::<PatchRequest>(&req_body)?;
 
         // Make path from param
@@ -159,12 +331,31 @@ impl Server {
         // Decode signature into bytes
         let sigb = base64::decode(&patch_req.sig)?;
 
+        // Never serve the server's own secret keyfile, whatever path names it
+        if std::fs::canonicalize(&path).ok() == std::fs::canonicalize(KEYFILE).ok() {
+            return Err("file not found".into());
+        }
+
+        // Announce the start of this patch to subscribers
+        let size = std::fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+        let _ = ctx.events.send(ProgressEvent::PatchStart {
+            file: path.clone(),
+            size,
+        });
+
         // Create delta patch for file according to given signature
         log::info!("Making patch for file {:?}", path);
         let patch = Self::make_patch(&path, &sigb[..])?;
 
-        // Respond with the patch
+        // Announce completion with the resulting patch size
+        let _ = ctx.events.send(ProgressEvent::PatchComplete {
+            file: path.clone(),
+            patch_size: patch.len(),
+        });
+
+        // Respond with the sealed patch
         log::info!("Patch size {}", bytes_pretty(patch.len() as f64));
+        let patch = crypto::seal(&key, &patch)?;
         let response = Response::builder()
             .status(StatusCode::OK)
             .body(Body::from(patch))
@@ -172,6 +363,46 @@ impl Server {
         Ok(response)
     }
 
+    fn shared_key(ctx: &ServerContext, req: &Request<Body>) -> Result<[u8; 32], Error> {
+        // Read the client's ephemeral public key from the request header
+        let header = req
+            .headers()
+            .get(CLIENT_PUBKEY_HEADER)
+            .ok_or("missing client public key header")?;
+        let pubb = base64::decode(header.as_bytes())?;
+        if pubb.len() != 32 {
+            return Err("invalid client public key length".into());
+        }
+        let mut bytes = [0u8; 32];
+        bytes.copy_from_slice(&pubb);
+
+        // Diffie-Hellman against our static secret and derive the AES key
+        let shared = ctx.secret.diffie_hellman(&PublicKey::from(bytes));
+        Ok(crypto::derive_key(shared.as_bytes()))
+    }
+
+    fn load_keypair() -> Result<StaticSecret, Error> {
+        let path = PathBuf::from(KEYFILE);
+        if path.exists() {
+            // Reuse the persisted secret so the public key stays stable across runs
+            let bytes = std::fs::read(&path)?;
+            if bytes.len() != 32 {
+                return Err("malformed server keyfile".into());
+            }
+            let mut key = [0u8; 32];
+            key.copy_from_slice(&bytes);
+            Ok(StaticSecret::from(key))
+        } else {
+            // Generate and persist a fresh secret on first run
+            log::info!("Generating server keypair into {}", KEYFILE);
+            let secret = StaticSecret::new(OsRng);
+            std::fs::write(&path, secret.to_bytes())?;
+            // Keep the secret readable only by its owner
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))?;
+            Ok(secret)
+        }
+    }
+
     async fn route_notfound(
         _ctx: &ServerContext,
         _req: Request<Body>,
@@ -211,6 +442,8 @@ impl Server {
                     .is_file()
                     .then(|| p.strip_prefix(dir).unwrap().to_path_buf())
             })
+            // Never serve the server's own secret keyfile
+            .filter(|p| p != &PathBuf::from(KEYFILE))
             .collect()
     }
 